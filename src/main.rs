@@ -15,49 +15,229 @@
  * producing CSV output of the closing balances of all accounts included in the input file
  */
 
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::error::Error;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
-use std::{io, process};
-
-use std::ffi::OsString;
+use clap::{Parser, Subcommand};
 
 mod transaction;
 
-/// run starts the main functionality of the toy_pay app
+/// Cli describes the command line arguments accepted by `toy_pay`.
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Alternate mode of operation; if omitted, `input` is processed as a
+    /// one-shot CSV file.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the input transactions CSV. Required unless `serve` is
+    /// given.
+    input: Option<String>,
+
+    /// Number of worker threads to shard client accounts across. Defaults
+    /// to single-threaded processing.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+}
+
+/// Command selects an alternate mode of operation.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve runs a TCP server: each connection streams line-delimited CSV
+    /// transaction records into a shared ledger, and a line reading
+    /// `balances` dumps the current account balances back as CSV.
+    Serve {
+        /// Address to bind the TCP listener to.
+        #[arg(long, default_value = "127.0.0.1:7878")]
+        bind: String,
+    },
+}
+
+/// run starts the main functionality of the toy_pay app.
 ///
-/// It will parse the requested csv file into the Record struct
-/// processing each row into the relevant accounts
+/// In its default mode it parses the requested csv file into the
+/// TransactionRecord struct, validates each row into a Transaction, and
+/// processes it into the relevant account, either on a single thread or
+/// sharded across a worker pool. In `serve` mode it instead accepts the
+/// same transactions over TCP connections.
 fn run() -> Result<(), Box<dyn Error>> {
-    // Create an empty hashmap to store the accounts in
-    let mut live_accounts = transaction::AccountRegistry::new();
+    let cli = Cli::parse();
+
+    if let Some(Command::Serve { bind }) = cli.command {
+        return run_server(&bind);
+    }
 
-    // Read the input provided via command line argument
-    let input_file = get_input_file()?;
-    let mut file_reader = csv::ReaderBuilder::new()
-        .flexible(false)
+    let input = cli.input.ok_or("No input file supplied")?;
+    // Rows are allowed to omit the trailing (empty) amount column, e.g. a
+    // short `dispute,1,1` rather than `dispute,1,1,` — flexible(true) lets
+    // the reader accept fewer fields instead of erroring the whole run,
+    // and TransactionRecord::amount's #[serde(default)] fills in `None`.
+    let file_reader = csv::ReaderBuilder::new()
+        .flexible(true)
         .trim(csv::Trim::All)
-        .from_path(input_file)?;
+        .from_path(input)?;
+
+    if cli.threads <= 1 {
+        run_single_threaded(file_reader)
+    } else {
+        run_sharded(file_reader, cli.threads)
+    }
+}
+
+// run_single_threaded processes every record on the current thread against
+// a single AccountRegistry.
+fn run_single_threaded(mut file_reader: csv::Reader<File>) -> Result<(), Box<dyn Error>> {
+    let mut live_accounts = transaction::AccountRegistry::new();
 
-    // Loop through each record of the provided csv
     for result in file_reader.records() {
-        let record: transaction::Record = result?.deserialize(None)?;
+        let record: transaction::TransactionRecord = result?.deserialize(None)?;
+        let transaction: transaction::Transaction = match record.try_into() {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                eprintln!("skipping malformed record: {}", err);
+                continue;
+            }
+        };
 
-        // Check to see if we already have the specified account, creating one if not
-        live_accounts.process_record(record);
+        live_accounts.process_record(transaction);
     }
-    // Output the found accounts
+
     live_accounts.output_records()
 }
 
-// get_input_file checks we have been provided with enough command line
-// arguments and returns the correct one
-fn get_input_file() -> Result<OsString, Box<dyn Error>> {
-    match std::env::args_os().nth(1) {
-        None => Err(From::from("No input file supplied")),
-        Some(file_path) => Ok(file_path),
+// run_sharded hashes each record's client onto one of `threads` worker
+// threads, so every transaction for a given client lands on the same
+// worker and per-client ordering is preserved. Each worker owns a
+// disjoint AccountRegistry; once the reader is drained, the shards are
+// merged into a single CSV write.
+fn run_sharded(mut file_reader: csv::Reader<File>, threads: usize) -> Result<(), Box<dyn Error>> {
+    let (senders, handles): (Vec<_>, Vec<_>) = (0..threads)
+        .map(|_| {
+            let (sender, receiver) = mpsc::channel::<transaction::Transaction>();
+            let handle = thread::spawn(move || {
+                let mut shard = transaction::AccountRegistry::new();
+                for transaction in receiver {
+                    shard.process_record(transaction);
+                }
+                shard.into_accounts()
+            });
+            (sender, handle)
+        })
+        .unzip();
+
+    for result in file_reader.records() {
+        let record: transaction::TransactionRecord = result?.deserialize(None)?;
+        let transaction: transaction::Transaction = match record.try_into() {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                eprintln!("skipping malformed record: {}", err);
+                continue;
+            }
+        };
+
+        senders[shard_for(transaction.client(), threads)].send(transaction)?;
+    }
+    // Dropping the senders closes every channel, letting each worker's
+    // `for transaction in receiver` loop end once it has drained its share.
+    drop(senders);
+
+    let mut wtr = csv::Writer::from_writer(io::stdout());
+    for handle in handles {
+        let accounts = handle.join().map_err(|_| "worker thread panicked")?;
+        for account in accounts {
+            wtr.serialize(account)?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+// shard_for picks which worker a client's transactions are routed to, by
+// hashing the client id modulo the worker count.
+fn shard_for(client: transaction::ClientId, threads: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    client.hash(&mut hasher);
+    (hasher.finish() as usize) % threads
+}
+
+// run_server binds a TCP listener at `bind` and hands each accepted
+// connection off to its own thread, all sharing one AccountRegistry
+// guarded by a mutex.
+fn run_server(bind: &str) -> Result<(), Box<dyn Error>> {
+    let registry = Arc::new(Mutex::new(transaction::AccountRegistry::new()));
+    let listener = TcpListener::bind(bind)?;
+
+    for incoming in listener.incoming() {
+        let stream = incoming?;
+        let registry = Arc::clone(&registry);
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, registry) {
+                eprintln!("connection error: {}", err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+// handle_connection reads line-delimited CSV transaction records from
+// `stream` as they arrive, feeding them into the shared registry with the
+// same TransactionRecord/Transaction pipeline the file mode uses. A line
+// reading `balances` dumps the registry's current account balances back
+// to the same connection as CSV.
+fn handle_connection(
+    stream: TcpStream,
+    registry: Arc<Mutex<transaction::AccountRegistry>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line == "balances" {
+            registry.lock().unwrap().write_records(&mut writer)?;
+            continue;
+        }
+
+        let mut row_reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(line.as_bytes());
+        let record: transaction::TransactionRecord = match row_reader.deserialize().next() {
+            Some(Ok(record)) => record,
+            Some(Err(err)) => {
+                eprintln!("skipping malformed line: {}", err);
+                continue;
+            }
+            None => continue,
+        };
+        let transaction: transaction::Transaction = match record.try_into() {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                eprintln!("skipping malformed line: {}", err);
+                continue;
+            }
+        };
+
+        registry.lock().unwrap().process_record(transaction);
     }
+
+    Ok(())
 }
 
 fn main() {
@@ -1,390 +1,834 @@
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::io;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
 
-#[derive(Debug, Deserialize)]
-pub struct Record {
-    r#type: String,
-    pub client: u16,
-    tx: u32,
-    amount: Option<f64>,
-    frozen: bool,
+/// Amount is an exact monetary value with four decimal places of precision,
+/// stored as a count of ten-thousandths so ledger arithmetic never
+/// accumulates floating-point error the way repeated `f64` addition does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Amount(i64);
+
+/// SCALE is the number of ten-thousandths in a single whole unit.
+const SCALE: i64 = 10_000;
+
+impl Amount {
+    /// ZERO is the additive identity, useful as a starting balance.
+    pub const ZERO: Amount = Amount(0);
 }
 
-pub struct AccountRegistry {
-    accounts: HashMap<u16, LiveAccount>,
+impl Add for Amount {
+    type Output = Amount;
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
 }
 
-impl AccountRegistry {
-    pub fn new() -> AccountRegistry {
-        return AccountRegistry {
-            accounts: HashMap::new(),
-        };
+impl Sub for Amount {
+    type Output = Amount;
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
     }
+}
 
-    fn add_account(&mut self, id: u16) -> &LiveAccount {
-        let fresh_account = LiveAccount {
-            transaction_record: HashMap::new(),
-            account_details: AccountDetails {
-                client: id,
-                available: 0.0,
-                held: 0.0,
-                total: 0.0,
-                locked: false,
-            },
-        };
-        self.accounts.insert(id, fresh_account);
-        &fresh_account
+impl Neg for Amount {
+    type Output = Amount;
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
     }
+}
+
+/// ParseAmountError is returned when a CSV field cannot be read as a
+/// four-decimal-place monetary amount.
+#[derive(Debug)]
+pub struct ParseAmountError(String);
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid amount: {:?}", self.0)
+    }
+}
+
+impl Error for ParseAmountError {}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
 
-    pub fn process_record(&mut self, record: Record) {
-        let account = match self.accounts.entry(record.client) {
-            Entry::Occupied(acc) => acc.into_mut(),
-            Entry::Vacant(acc) => self.add_account(record.client),
+    // from_str splits on the decimal point and takes at most four
+    // fractional digits, truncating anything beyond that, then combines
+    // the whole and fractional parts into a single ten-thousandths count.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = || ParseAmountError(s.to_string());
+
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let mut parts = unsigned.splitn(2, '.');
+        let whole: i64 = parts
+            .next()
+            .filter(|whole| !whole.is_empty())
+            .ok_or_else(bad)?
+            .parse()
+            .map_err(|_| bad())?;
+        let frac: i64 = match parts.next() {
+            Some(digits) if !digits.is_empty() => {
+                let truncated = &digits[..digits.len().min(4)];
+                format!("{:0<4}", truncated).parse().map_err(|_| bad())?
+            }
+            _ => 0,
         };
 
-        account.process_transaction(record);
+        let magnitude = whole * SCALE + frac;
+        Ok(Amount(if negative { -magnitude } else { magnitude }))
     }
+}
 
-    pub fn output_records(&self) -> Result<(), Box<dyn Error>> {
-        let mut wtr = csv::Writer::from_writer(io::stdout());
-        for account in self.accounts.values() {
-            wtr.serialize(account.account_details)?
+impl fmt::Display for Amount {
+    // fmt renders the amount as whole.fraction, trimming trailing zeroes
+    // and the decimal point entirely when the fraction is zero.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let magnitude = self.0.abs();
+        let whole = magnitude / SCALE;
+        let frac = magnitude % SCALE;
+
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+        if frac == 0 {
+            write!(f, "{}", whole)
+        } else {
+            write!(f, "{}.{}", whole, format!("{:04}", frac).trim_end_matches('0'))
+        }
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// ClientId uniquely identifies a client account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct ClientId(pub u16);
+
+/// TxId uniquely identifies a single transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub struct TxId(pub u32);
+
+/// TransactionRecord is the raw shape of a row in the input CSV, before it
+/// has been checked against the invariants of the transaction type it
+/// names.
+#[derive(Debug, Deserialize)]
+pub struct TransactionRecord {
+    r#type: String,
+    pub client: ClientId,
+    tx: TxId,
+    #[serde(default)]
+    amount: Option<Amount>,
+}
+
+/// Transaction is a validated transaction: every variant only carries the
+/// fields that make sense for it, so there's no way to hold e.g. a dispute
+/// with an amount attached.
+#[derive(Debug, Clone, Copy)]
+pub enum Transaction {
+    /// Deposit credits `amount` to the client's available balance.
+    Deposit {
+        client: ClientId,
+        tx: TxId,
+        amount: Amount,
+    },
+    /// Withdrawal debits `amount` from the client's available balance.
+    Withdrawal {
+        client: ClientId,
+        tx: TxId,
+        amount: Amount,
+    },
+    /// Dispute claims that `tx` was erroneous.
+    Dispute { client: ClientId, tx: TxId },
+    /// Resolve closes a dispute in the client's favour.
+    Resolve { client: ClientId, tx: TxId },
+    /// Chargeback closes a dispute by reversing `tx` and locking the
+    /// account.
+    Chargeback { client: ClientId, tx: TxId },
+}
+
+impl Transaction {
+    /// client returns the account this transaction applies to.
+    pub fn client(&self) -> ClientId {
+        match *self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::Chargeback { client, .. } => client,
+        }
+    }
+}
+
+/// ParseError is returned when a `TransactionRecord` doesn't satisfy the
+/// invariants of the `Transaction` its type names.
+#[derive(Debug)]
+pub enum ParseError {
+    /// MissingAmount means a deposit or withdrawal had no `amount` field.
+    MissingAmount,
+    /// UnexpectedAmount means a dispute, resolve, or chargeback carried an
+    /// `amount` field, which those types forbid.
+    UnexpectedAmount,
+    /// UnknownType means the `type` field didn't name a supported
+    /// transaction.
+    UnknownType(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingAmount => write!(f, "transaction is missing its amount"),
+            ParseError::UnexpectedAmount => write!(f, "transaction should not carry an amount"),
+            ParseError::UnknownType(kind) => write!(f, "unknown transaction type: {:?}", kind),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = ParseError;
+
+    // try_from enforces amount invariants at parse time: deposits and
+    // withdrawals require an amount, disputes/resolves/chargebacks forbid
+    // one, so a malformed row is rejected here rather than silently
+    // dropped later.
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let TransactionRecord {
+            r#type,
+            client,
+            tx,
+            amount,
+        } = record;
+
+        match r#type.as_str() {
+            "deposit" => Ok(Transaction::Deposit {
+                client,
+                tx,
+                amount: amount.ok_or(ParseError::MissingAmount)?,
+            }),
+            "withdrawal" => Ok(Transaction::Withdrawal {
+                client,
+                tx,
+                amount: amount.ok_or(ParseError::MissingAmount)?,
+            }),
+            "dispute" => {
+                if amount.is_some() {
+                    return Err(ParseError::UnexpectedAmount);
+                }
+                Ok(Transaction::Dispute { client, tx })
+            }
+            "resolve" => {
+                if amount.is_some() {
+                    return Err(ParseError::UnexpectedAmount);
+                }
+                Ok(Transaction::Resolve { client, tx })
+            }
+            "chargeback" => {
+                if amount.is_some() {
+                    return Err(ParseError::UnexpectedAmount);
+                }
+                Ok(Transaction::Chargeback { client, tx })
+            }
+            other => Err(ParseError::UnknownType(other.to_string())),
         }
-        wtr.flush()?;
-        Ok(())
     }
 }
 
 #[derive(Debug, Serialize)]
 pub struct AccountDetails {
-    client: u16,
-    available: f64,
-    held: f64,
-    total: f64,
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    total: Amount,
     locked: bool,
 }
 
 impl AccountDetails {
-    // round_values ensures precision to four decimal places
-    fn round_values(&mut self) {
-        self.held = (self.held * 10000.0).round() / 10000.0;
-        self.total = (self.total * 10000.0).round() / 10000.0;
-        self.available = (self.available * 10000.0).round() / 10000.0;
+    fn new(client: ClientId) -> AccountDetails {
+        AccountDetails {
+            client,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
+            locked: false,
+        }
     }
 
     fn recompute_total(&mut self) {
         self.total = self.available + self.held;
-        self.round_values()
     }
 }
 
-pub struct LiveAccount {
-    transaction_record: HashMap<u32, Record>,
-    account_details: AccountDetails,
+/// TxState tracks where a single transaction sits in the dispute lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxState {
+    /// Processed is the normal state of a deposit or withdrawal that has
+    /// not been disputed.
+    Processed,
+    /// Disputed means the transaction's amount has been moved from
+    /// available to held, pending a resolve or chargeback.
+    Disputed,
+    /// Resolved means a dispute was resolved in the client's favour and the
+    /// amount moved back from held to available.
+    Resolved,
+    /// ChargedBack means a dispute was upheld, the amount was removed from
+    /// held, and the account is now locked.
+    ChargedBack,
+}
+
+/// LedgerError describes why a transaction could not be applied to an
+/// account, replacing the bare `return`s that used to swallow every
+/// failure silently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerError {
+    /// NotEnoughFunds means a withdrawal asked for more than the account's
+    /// available balance.
+    NotEnoughFunds,
+    /// NonPositiveAmount means a deposit or withdrawal carried a zero or
+    /// negative amount, which would otherwise fabricate or destroy funds.
+    NonPositiveAmount,
+    /// UnknownTx means a dispute, resolve, or chargeback named a
+    /// transaction this account has no record of.
+    UnknownTx(ClientId, TxId),
+    /// AlreadyDisputed means a dispute was raised for a transaction that
+    /// isn't in the `Processed` state, e.g. a double dispute.
+    AlreadyDisputed,
+    /// NotDisputed means a resolve or chargeback was raised for a
+    /// transaction that isn't currently under dispute.
+    NotDisputed,
+    /// FrozenAccount means the account is locked and can no longer process
+    /// transactions.
+    FrozenAccount,
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::NotEnoughFunds => write!(f, "insufficient available funds"),
+            LedgerError::NonPositiveAmount => write!(f, "amount must be positive"),
+            LedgerError::UnknownTx(client, tx) => {
+                write!(f, "client {} has no record of tx {}", client.0, tx.0)
+            }
+            LedgerError::AlreadyDisputed => write!(f, "transaction is already disputed"),
+            LedgerError::NotDisputed => write!(f, "transaction is not under dispute"),
+            LedgerError::FrozenAccount => write!(f, "account is locked"),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+/// TxEntry is the per-transaction state a `Store` must retain: the
+/// deposited or withdrawn amount, and where that transaction currently
+/// sits in its dispute lifecycle.
+#[derive(Debug, Clone, Copy)]
+pub struct TxEntry {
+    amount: Amount,
+    state: TxState,
+}
+
+/// Store abstracts the persistence layer behind `AccountRegistry`. The
+/// in-memory `MemStore` keeps every account and transaction resident for
+/// the lifetime of the process; a disk- or embedded-db-backed
+/// implementation could instead page accounts in and out, letting inputs
+/// larger than RAM be processed.
+pub trait Store {
+    /// get_account returns the account for `client`, creating a fresh
+    /// zero-balance one if this is the first time it's been seen.
+    fn get_account(&mut self, client: ClientId) -> &mut AccountDetails;
+
+    /// accounts iterates over every account currently tracked, for output
+    /// of their current balances.
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountDetails> + '_>;
+
+    /// get_tx returns the stored amount and state for `tx` on `client`'s
+    /// account, if this account has a record of it.
+    fn get_tx(&self, client: ClientId, tx: TxId) -> Option<TxEntry>;
+
+    /// put_tx records the amount and state for `tx` on `client`'s account.
+    fn put_tx(&mut self, client: ClientId, tx: TxId, entry: TxEntry);
+
+    /// forget_tx drops a transaction's stored body. Callers only do this
+    /// once a tx has reached a terminal `Resolved`/`ChargedBack` state and
+    /// it will never be looked up again.
+    fn forget_tx(&mut self, client: ClientId, tx: TxId);
+
+    /// into_accounts consumes the store, returning every account's final
+    /// details. Used to merge independent shards after parallel
+    /// processing.
+    fn into_accounts(self) -> Vec<AccountDetails>
+    where
+        Self: Sized;
+}
+
+/// MemStore is the straightforward in-memory `Store`: every account and
+/// transaction lives in a `HashMap` for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemStore {
+    accounts: HashMap<ClientId, AccountDetails>,
+    transactions: HashMap<(ClientId, TxId), TxEntry>,
+}
+
+impl Store for MemStore {
+    fn get_account(&mut self, client: ClientId) -> &mut AccountDetails {
+        self.accounts
+            .entry(client)
+            .or_insert_with(|| AccountDetails::new(client))
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = &AccountDetails> + '_> {
+        Box::new(self.accounts.values())
+    }
+
+    fn get_tx(&self, client: ClientId, tx: TxId) -> Option<TxEntry> {
+        self.transactions.get(&(client, tx)).copied()
+    }
+
+    fn put_tx(&mut self, client: ClientId, tx: TxId, entry: TxEntry) {
+        self.transactions.insert((client, tx), entry);
+    }
+
+    fn forget_tx(&mut self, client: ClientId, tx: TxId) {
+        self.transactions.remove(&(client, tx));
+    }
+
+    fn into_accounts(self) -> Vec<AccountDetails> {
+        self.accounts.into_values().collect()
+    }
+}
+
+/// AccountRegistry owns every account and dispatches transactions to them,
+/// backed by a generic `Store` so callers can swap in a different
+/// persistence strategy for inputs that don't fit in memory.
+pub struct AccountRegistry<S: Store = MemStore> {
+    store: S,
+}
+
+impl AccountRegistry<MemStore> {
+    pub fn new() -> AccountRegistry<MemStore> {
+        AccountRegistry {
+            store: MemStore::default(),
+        }
+    }
 }
 
-impl LiveAccount {
-    // process_transaction receives a record and calls the relevant function
-    // depending on what action is provided in the record
-    pub fn process_transaction(&mut self, record: Record) {
-        match record.r#type.as_str() {
-            "deposit" => self.deposit(record),
-            "withdrawal" => self.withdraw(record),
-            "dispute" => self.dispute(record),
-            "resolve" => self.resolve(record),
-            "chargeback" => self.chargeback(record),
-            _ => return,
+impl<S: Store> AccountRegistry<S> {
+    /// with_store builds a registry backed by an already-constructed
+    /// `Store`, for callers that want something other than `MemStore`.
+    pub fn with_store(store: S) -> AccountRegistry<S> {
+        AccountRegistry { store }
+    }
+
+    // process_record applies a transaction to its account, logging any
+    // `LedgerError` to stderr and continuing with the rest of the stream
+    // rather than aborting the whole run.
+    pub fn process_record(&mut self, transaction: Transaction) {
+        let client = transaction.client();
+        if let Err(err) = self.process_transaction(transaction) {
+            eprintln!("client {}: {}", client.0, err);
+        }
+    }
+
+    // process_transaction dispatches on the transaction variant and
+    // returns a `LedgerError` on any failure instead of silently ignoring
+    // it, so the caller can surface it to the operator.
+    fn process_transaction(&mut self, transaction: Transaction) -> Result<(), LedgerError> {
+        let client = transaction.client();
+        let result = match transaction {
+            Transaction::Deposit { tx, amount, .. } => self.deposit(client, tx, amount),
+            Transaction::Withdrawal { tx, amount, .. } => self.withdraw(client, tx, amount),
+            Transaction::Dispute { tx, .. } => self.dispute(client, tx),
+            Transaction::Resolve { tx, .. } => self.resolve(client, tx),
+            Transaction::Chargeback { tx, .. } => self.chargeback(client, tx),
         };
         // Recompute the total for the account
-        self.account_details.recompute_total();
+        self.store.get_account(client).recompute_total();
+        result
     }
 
-    fn deposit(&mut self, record: Record) {
-        if self.account_details.locked {
-            return;
+    fn deposit(&mut self, client: ClientId, tx: TxId, amount: Amount) -> Result<(), LedgerError> {
+        if amount <= Amount::ZERO {
+            return Err(LedgerError::NonPositiveAmount);
+        }
+        let account = self.store.get_account(client);
+        if account.locked {
+            return Err(LedgerError::FrozenAccount);
         }
         // Increase the available cash
-        match record.amount {
-            Some(amount) => self.account_details.available += amount,
-            None => return,
-        };
+        account.available += amount;
         // Add the transaction to the account's transaction list
-        self.transaction_record.insert(record.tx, record);
+        self.store.put_tx(
+            client,
+            tx,
+            TxEntry {
+                amount,
+                state: TxState::Processed,
+            },
+        );
+        Ok(())
     }
-    
-    fn withdraw(&mut self, record: Record) {
-        if self.account_details.locked {
-            return;
+
+    fn withdraw(&mut self, client: ClientId, tx: TxId, amount: Amount) -> Result<(), LedgerError> {
+        if amount <= Amount::ZERO {
+            return Err(LedgerError::NonPositiveAmount);
         }
-        // Decrease the account's available cash
-        match record.amount {
-            Some(amount) => self.account_details.available -= amount,
-            None => return,
-        };
+        let account = self.store.get_account(client);
+        if account.locked {
+            return Err(LedgerError::FrozenAccount);
+        }
+        // Reject rather than drive the available balance negative
+        if amount > account.available {
+            return Err(LedgerError::NotEnoughFunds);
+        }
+        account.available -= amount;
         // Add the transaction to the account's transaction list
-        self.transaction_record.insert(record.tx, record);
+        self.store.put_tx(
+            client,
+            tx,
+            TxEntry {
+                amount,
+                state: TxState::Processed,
+            },
+        );
+        Ok(())
     }
-    fn dispute(&mut self, record: Record) {
-        if self.account_details.locked{
-            return;
-        }
-        // Remove the disputed transaction from the normal transaction list, if not found
-        // then assume an error has occoured and do nothing
-        let transaction: &Record = match self.transaction_record.get(&record.tx) {
-            Some(record) => record,
-            None => return,
-        };
 
-        if transaction.frozen{
-            return;
+    fn dispute(&mut self, client: ClientId, tx: TxId) -> Result<(), LedgerError> {
+        if self.store.get_account(client).locked {
+            return Err(LedgerError::FrozenAccount);
         }
-        // Decrease the amount of cash from the available pot and add it to the held pot
-        match transaction.amount {
-            Some(amount) => {
-                self.account_details.available -= amount;
-                self.account_details.held += amount;
-            },
-            None => return,
+        let entry = self
+            .store
+            .get_tx(client, tx)
+            .ok_or(LedgerError::UnknownTx(client, tx))?;
+        if entry.state != TxState::Processed {
+            return Err(LedgerError::AlreadyDisputed);
         }
-        transaction.frozen = true;
+        // Move the disputed amount from available to held
+        let account = self.store.get_account(client);
+        account.available -= entry.amount;
+        account.held += entry.amount;
+        self.store.put_tx(
+            client,
+            tx,
+            TxEntry {
+                state: TxState::Disputed,
+                ..entry
+            },
+        );
+        Ok(())
     }
-    fn resolve(&mut self, record: Record) {
-        if self.account_details.locked {
-            return;
+
+    fn resolve(&mut self, client: ClientId, tx: TxId) -> Result<(), LedgerError> {
+        if self.store.get_account(client).locked {
+            return Err(LedgerError::FrozenAccount);
         }
-        // Remove the disputed transaction from the frozen transaction list, if not found
-        // then assume an error has occoured and do nothing
-        let transaction: &Record = match self.transaction_record.get(&record.tx) {
-            Some(record) => record,
-            None => return,
-        };
-        // If the transaction isn't frozen then this isn't a valid transaction
-        if !transaction.frozen {
-            return;
+        let entry = self
+            .store
+            .get_tx(client, tx)
+            .ok_or(LedgerError::UnknownTx(client, tx))?;
+        if entry.state != TxState::Disputed {
+            return Err(LedgerError::NotDisputed);
         }
-        transaction.frozen = false;
-        // Decrease the amount of cash from the held pot and add it to the available pot
-        match transaction.amount {
-            Some(amount) => {
-                self.account_details.available += amount;
-                self.account_details.held -= amount;
+        // Move the disputed amount back from held to available
+        let account = self.store.get_account(client);
+        account.available += entry.amount;
+        account.held -= entry.amount;
+        // Keep the entry around in its terminal state rather than
+        // forgetting it, so a later dispute/resolve/chargeback against the
+        // same tx gets a well-defined AlreadyDisputed/NotDisputed error
+        // instead of looking entirely unseen.
+        self.store.put_tx(
+            client,
+            tx,
+            TxEntry {
+                state: TxState::Resolved,
+                ..entry
             },
-            None => return,
+        );
+        Ok(())
+    }
+
+    fn chargeback(&mut self, client: ClientId, tx: TxId) -> Result<(), LedgerError> {
+        if self.store.get_account(client).locked {
+            return Err(LedgerError::FrozenAccount);
         }
-        // Add the previously frozen transaction to the normal transaction list
-    }
-    fn chargeback(&mut self, record: Record) {
-        // Remove the disputed transaction from the frozen transaction list, if not found
-        // then assume an error has occoured and do nothing
-        let transaction: &Record = match self.transaction_record.get(&record.tx) {
-            Some(record) => record,
-            None => return,
-        };
-        if !transaction.frozen {
-            return;
+        let entry = self
+            .store
+            .get_tx(client, tx)
+            .ok_or(LedgerError::UnknownTx(client, tx))?;
+        if entry.state != TxState::Disputed {
+            return Err(LedgerError::NotDisputed);
         }
-        // Remove the amount in question from the held pot
-        match transaction.amount {
-            Some(amount) => {
-                self.account_details.held -= amount;
+        // Remove the disputed amount from held and lock the account
+        let account = self.store.get_account(client);
+        account.held -= entry.amount;
+        account.locked = true;
+        // Keep the entry around in its terminal state rather than
+        // forgetting it. The account is now locked, so any later
+        // dispute/resolve/chargeback against it is rejected with
+        // FrozenAccount before the entry is looked up.
+        self.store.put_tx(
+            client,
+            tx,
+            TxEntry {
+                state: TxState::ChargedBack,
+                ..entry
             },
-            None => return,
+        );
+        Ok(())
+    }
+
+    /// into_accounts consumes the registry, returning every account's
+    /// final details. Used to merge a parallel run's per-shard registries
+    /// into a single output.
+    pub fn into_accounts(self) -> Vec<AccountDetails> {
+        self.store.into_accounts()
+    }
+
+    /// output_records writes every account's current details as CSV to
+    /// stdout.
+    pub fn output_records(&self) -> Result<(), Box<dyn Error>> {
+        self.write_records(io::stdout())
+    }
+
+    /// write_records serializes every account's current details as CSV to
+    /// `writer`. Shared by the one-shot file mode's stdout output and the
+    /// server mode's `balances` dump.
+    pub fn write_records<W: io::Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for account in self.store.accounts() {
+            wtr.serialize(account)?
         }
-        // Lock the account
-        self.account_details.locked = true;
+        wtr.flush()?;
+        Ok(())
     }
 }
 
+#[cfg(test)]
 mod tests {
-
     use super::*;
 
-    // Allowing dead code here as this struct is only used in testing
-    #[allow(dead_code)]
-    pub struct TestCase {
-        record: Record,
-        account: Account,
-        expected_total: f32,
-        expected_held: f32,
-        expected_available: f32,
-        expected_locked: bool,
+    #[test]
+    fn with_store_builds_a_registry_backed_by_a_given_store() {
+        let mut registry = AccountRegistry::with_store(MemStore::default());
+        registry
+            .process_transaction(Transaction::Deposit {
+                client: ClientId(1),
+                tx: TxId(1),
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+
+        let account = registry
+            .into_accounts()
+            .into_iter()
+            .find(|account| account.client == ClientId(1))
+            .unwrap();
+        assert_eq!(account.available, "10.0".parse().unwrap());
     }
 
-    // run_test_cases runs through a set of configured test cases
-    // designed to ensure the main record processing actions completed
-    // as expected
     #[test]
-    fn run_test_cases() {
-        let test_cases: Vec<TestCase> = vec![
-            // Deposit
-            TestCase {
-                record: Record {
-                    r#type: String::from("deposit"),
-                    client: 1,
-                    tx: 1,
-                    amount: Some(10.0),
-                },
-                account: Account {
-                    transactions: HashMap::new(),
-                    frozen_transactions: HashMap::new(),
-                    client: 1,
-                    available: 0.0,
-                    held: 0.0,
-                    locked: false,
-                    total: 0.0,
-                },
-                expected_total: 10.0,
-                expected_held: 0.0,
-                expected_available: 10.0,
-                expected_locked: false,
-            },
-            // Withdraw
-            TestCase {
-                record: Record {
-                    r#type: String::from("withdrawal"),
-                    client: 1,
-                    tx: 1,
-                    amount: Some(10.0),
-                },
-                account: Account {
-                    transactions: HashMap::new(),
-                    frozen_transactions: HashMap::new(),
-                    client: 1,
-                    available: 20.0,
-                    held: 0.0,
-                    locked: false,
-                    total: 0.0,
-                },
-                expected_total: 10.0,
-                expected_held: 0.0,
-                expected_available: 10.0,
-                expected_locked: false,
-            },
-            // Dispute
-            TestCase {
-                record: Record {
-                    r#type: String::from("dispute"),
-                    client: 1,
-                    tx: 1,
-                    amount: None,
-                },
-                account: Account {
-                    transactions: HashMap::from([(
-                        1,
-                        Record {
-                            r#type: String::from("deposit"),
-                            client: 1,
-                            tx: 1,
-                            amount: Some(10.0),
-                        },
-                    )]),
-                    frozen_transactions: HashMap::new(),
-                    client: 1,
-                    available: 10.0,
-                    held: 0.0,
-                    locked: false,
-                    total: 0.0,
-                },
-                expected_total: 10.0,
-                expected_held: 10.0,
-                expected_available: 0.0,
-                expected_locked: false,
-            },
-            // Resolve
-            TestCase {
-                record: Record {
-                    r#type: String::from("resolve"),
-                    client: 1,
-                    tx: 1,
-                    amount: None,
-                },
-                account: Account {
-                    transactions: HashMap::new(),
-                    frozen_transactions: HashMap::from([(
-                        1,
-                        Record {
-                            r#type: String::from("deposit"),
-                            client: 1,
-                            tx: 1,
-                            amount: Some(10.0),
-                        },
-                    )]),
-                    client: 1,
-                    available: 0.0,
-                    held: 10.0,
-                    locked: false,
-                    total: 10.0,
-                },
-                expected_total: 10.0,
-                expected_held: 0.0,
-                expected_available: 10.0,
-                expected_locked: false,
-            },
-            // Chargeback
-            TestCase {
-                record: Record {
-                    r#type: String::from("chargeback"),
-                    client: 1,
-                    tx: 1,
-                    amount: None,
-                },
-                account: Account {
-                    transactions: HashMap::new(),
-                    frozen_transactions: HashMap::from([(
-                        1,
-                        Record {
-                            r#type: String::from("deposit"),
-                            client: 1,
-                            tx: 1,
-                            amount: Some(10.0),
-                        },
-                    )]),
-                    client: 1,
-                    available: 0.0,
-                    held: 10.0,
-                    locked: false,
-                    total: 10.0,
-                },
-                expected_total: 0.0,
-                expected_held: 0.0,
-                expected_available: 0.0,
-                expected_locked: true,
-            },
-        ];
-        for test_case in test_cases {
-            println!("Runing test case for {}", test_case.record.r#type);
-            let mut test_account = test_case.account;
-            let test_transaction = test_case.record;
-            test_account.process_transaction(test_transaction);
-            assert_eq!(test_account.total, test_case.expected_total);
-            assert_eq!(test_account.held, test_case.expected_held);
-            assert_eq!(test_account.available, test_case.expected_available);
-            assert_eq!(test_account.locked, test_case.expected_locked);
-        }
+    fn amount_from_str_truncates_beyond_four_decimals() {
+        let amount: Amount = "1.23456".parse().unwrap();
+        assert_eq!(amount.to_string(), "1.2345");
     }
 
     #[test]
-    fn test_unsupported_action() {
-        // Given a test account
-        let mut test_account = new_account(1);
-        // and a record with an unupported transaction
-        let test_transaction = Record {
-            r#type: String::from("unsupported_action"),
-            client: 1,
-            tx: 1,
-            amount: Some(10.0),
-        };
-        // When the transaction is processed
-        test_account.process_transaction(test_transaction);
-
-        // Then the account is not updated
-        test_account.transactions.is_empty();
-        assert!(test_account.available == 0.0);
-        assert!(test_account.total == 0.0);
-        assert!(test_account.held == 0.0);
-        assert!(test_account.locked == false);
+    fn amount_from_str_parses_negative() {
+        let amount: Amount = "-5.5".parse().unwrap();
+        assert_eq!(amount, -"5.5".parse::<Amount>().unwrap());
+    }
+
+    #[test]
+    fn deposit_rejects_non_positive_amount() {
+        let mut registry = AccountRegistry::new();
+        let err = registry
+            .process_transaction(Transaction::Deposit {
+                client: ClientId(1),
+                tx: TxId(1),
+                amount: "-5.0".parse().unwrap(),
+            })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::NonPositiveAmount);
+    }
+
+    #[test]
+    fn withdraw_rejects_non_positive_amount() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx: TxId(1),
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+
+        let err = registry
+            .process_transaction(Transaction::Withdrawal {
+                client,
+                tx: TxId(2),
+                amount: Amount::ZERO,
+            })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::NonPositiveAmount);
+    }
+
+    #[test]
+    fn dispute_resolve_round_trip_restores_available() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        let tx = TxId(1);
+        registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+        registry
+            .process_transaction(Transaction::Dispute { client, tx })
+            .unwrap();
+        registry
+            .process_transaction(Transaction::Resolve { client, tx })
+            .unwrap();
+
+        let account = registry
+            .into_accounts()
+            .into_iter()
+            .find(|account| account.client == client)
+            .unwrap();
+        assert_eq!(account.available, "10.0".parse().unwrap());
+        assert_eq!(account.held, Amount::ZERO);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn double_dispute_is_rejected() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        let tx = TxId(1);
+        registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+        registry
+            .process_transaction(Transaction::Dispute { client, tx })
+            .unwrap();
+
+        let err = registry
+            .process_transaction(Transaction::Dispute { client, tx })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::AlreadyDisputed);
+    }
+
+    #[test]
+    fn resolve_without_dispute_is_rejected() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        let tx = TxId(1);
+        registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+
+        let err = registry
+            .process_transaction(Transaction::Resolve { client, tx })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::NotDisputed);
+    }
+
+    #[test]
+    fn dispute_of_unknown_tx_is_rejected() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        let tx = TxId(99);
+
+        let err = registry
+            .process_transaction(Transaction::Dispute { client, tx })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::UnknownTx(client, tx));
+    }
+
+    #[test]
+    fn chargeback_locks_the_account_and_blocks_further_transactions() {
+        let mut registry = AccountRegistry::new();
+        let client = ClientId(1);
+        let tx = TxId(1);
+        registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx,
+                amount: "10.0".parse().unwrap(),
+            })
+            .unwrap();
+        registry
+            .process_transaction(Transaction::Dispute { client, tx })
+            .unwrap();
+        registry
+            .process_transaction(Transaction::Chargeback { client, tx })
+            .unwrap();
+
+        let err = registry
+            .process_transaction(Transaction::Deposit {
+                client,
+                tx: TxId(2),
+                amount: "1.0".parse().unwrap(),
+            })
+            .unwrap_err();
+        assert_eq!(err, LedgerError::FrozenAccount);
+
+        let account = registry
+            .into_accounts()
+            .into_iter()
+            .find(|account| account.client == client)
+            .unwrap();
+        assert!(account.locked);
+        assert_eq!(account.held, Amount::ZERO);
     }
 }